@@ -1,5 +1,5 @@
-use clap::{Args, Parser, Subcommand};
-use std::path::PathBuf;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::path::{Component, Path, PathBuf};
 
 use crate::db;
 
@@ -23,11 +23,80 @@ pub enum Commands {
 
     #[command(name = "export", alias = "e", about = "Export a playlist from specified tags")]
     Export {
+        // Tag condition expression, e.g. "(energy>=7 OR mood>=7) AND background<4"
+        tags: Vec<String>,
+
+        #[arg(long, value_enum, default_value_t = ExportFormat::Text)]
+        format: ExportFormat,
+
+        #[arg(long, help = "Write the playlist to this file instead of stdout")]
+        output: Option<PathBuf>,
+
+        #[arg(long, help = "Write paths relative to the playlist's directory")]
+        relative: bool,
+    },
+
+    #[command(name = "sql", about = "Run a raw SQL query against the database")]
+    Sql {
+        // The SQL statement to run, e.g. "SELECT * FROM songs"
+        query: String,
+
+        #[arg(long, default_value_t = 100)]
+        limit: i64,
+
+        #[arg(long, help = "Allow statements other than SELECT/PRAGMA")]
+        write: bool,
+    },
+
+    #[command(name = "recommend", alias = "rec", about = "Recommend songs from play history and feedback")]
+    Recommend {
         // Tag conditions, e.g. energy>=7 mood<5 background=3
         tags: Vec<String>,
+
+        #[arg(long, default_value_t = 10)]
+        count: usize,
+
+        #[arg(long, default_value = "30d", help = "How far back to weigh plays, e.g. 30d or 1y")]
+        window: String,
+
+        #[arg(long, default_value_t = 7.0, help = "Half-life in days for play/feedback recency decay")]
+        halflife: f64,
+
+        #[arg(long, help = "Bypass weighting and sample uniformly at random")]
+        random: bool,
+    },
+
+    #[command(name = "scan", about = "Scan a directory and sync it into the database")]
+    Scan {
+        dir: PathBuf,
+
+        #[arg(long, help = "Remove songs whose path no longer exists on disk")]
+        prune: bool,
+    },
+
+    #[command(name = "play", about = "Log plays for songs matching a query, with optional per-tag feedback")]
+    Play {
+        // Tag condition expression, e.g. "(energy>=7 OR mood>=7) AND background<4"
+        tags: Vec<String>,
+
+        #[arg(long, help = "Mark all matched plays as skipped instead of completed")]
+        skip: bool,
+
+        #[arg(long = "feedback", help = "Feedback for a tag that drove the selection, e.g. energy=+1")]
+        feedback: Vec<String>,
     },
 }
 
+// Playlist file format for `export --output`
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ExportFormat {
+    Text,
+    M3u,
+    M3u8,
+    Pls,
+    Json,
+}
+
 #[derive(Args)]
 pub struct SongArgs {
     pub path: PathBuf,
@@ -84,8 +153,136 @@ fn parse_tag_condition(condition: &str) -> Result<(String, u8, String), String>
     Err(format!("No valid operator found in condition: {} (use =, >, <, >=, <=, !=)", condition))
 }
 
+// Split a tag condition expression into "(", ")", and condition/keyword tokens
+fn tokenize_cond_expr(input: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+
+    for ch in input.chars() {
+        match ch {
+            '(' | ')' => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+                tokens.push(ch.to_string());
+            }
+            c if c.is_whitespace() => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+
+    tokens
+}
+
+// Recursive-descent parser for the export grammar: OR binds loosest, AND binds
+// tighter, parentheses group, and a bare token is a leaf tag condition.
+struct CondParser<'a> {
+    tokens: &'a [String],
+    pos: usize,
+}
+
+impl<'a> CondParser<'a> {
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        self.tokens.get(self.pos).map(|t| t.eq_ignore_ascii_case(keyword)).unwrap_or(false)
+    }
+
+    fn parse_expr(&mut self) -> Result<db::Cond, String> {
+        let mut terms = vec![self.parse_and()?];
+        while self.peek_keyword("OR") {
+            self.pos += 1;
+            terms.push(self.parse_and()?);
+        }
+        Ok(if terms.len() == 1 { terms.pop().unwrap() } else { db::Cond::Or(terms) })
+    }
+
+    fn parse_and(&mut self) -> Result<db::Cond, String> {
+        let mut terms = vec![self.parse_term()?];
+        while self.peek_keyword("AND") {
+            self.pos += 1;
+            terms.push(self.parse_term()?);
+        }
+        Ok(if terms.len() == 1 { terms.pop().unwrap() } else { db::Cond::And(terms) })
+    }
+
+    fn parse_term(&mut self) -> Result<db::Cond, String> {
+        match self.tokens.get(self.pos) {
+            Some(tok) if tok == "(" => {
+                self.pos += 1;
+                let inner = self.parse_expr()?;
+                match self.tokens.get(self.pos) {
+                    Some(tok) if tok == ")" => {
+                        self.pos += 1;
+                        Ok(inner)
+                    }
+                    _ => Err("Expected closing ')'".to_string()),
+                }
+            }
+            Some(tok) => {
+                let (name, value, operator) = parse_tag_condition(tok)?;
+                self.pos += 1;
+                Ok(db::Cond::Leaf(name, value, operator))
+            }
+            None => Err("Unexpected end of expression".to_string()),
+        }
+    }
+}
+
+// Parse an export tag expression like "(energy>=7 OR mood>=7) AND background<4"
+// into a `db::Cond` tree
+fn parse_cond_expr(input: &str) -> Result<db::Cond, String> {
+    let tokens = tokenize_cond_expr(input);
+    if tokens.is_empty() {
+        return Err("No tag conditions specified".to_string());
+    }
+
+    let mut parser = CondParser { tokens: &tokens, pos: 0 };
+    let cond = parser.parse_expr()?;
+    if parser.pos != tokens.len() {
+        return Err(format!("Unexpected token: {}", tokens[parser.pos]));
+    }
+
+    Ok(cond)
+}
+
+// Parse a feedback arg like "energy=+1" or "mood=-1" into (tag_name, sign)
+fn parse_feedback(arg: &str) -> Result<(String, i64), String> {
+    let (name, sign_str) = arg.split_once('=').ok_or_else(|| format!("Expected tag=sign in: {}", arg))?;
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        return Err(format!("Empty tag name in feedback: {}", arg));
+    }
+    match sign_str.trim() {
+        "+1" | "1" => Ok((name, 1)),
+        "-1" => Ok((name, -1)),
+        other => Err(format!("Feedback sign must be +1 or -1, got: {}", other)),
+    }
+}
+
+// Parse a duration string like "30d" or "1y" into a number of days
+fn parse_window_days(window: &str) -> Result<f64, String> {
+    let window = window.trim();
+    if window.len() < 2 {
+        return Err(format!("Invalid window: {}", window));
+    }
+    let (count_str, unit) = window.split_at(window.len() - 1);
+    let count: f64 = count_str.parse().map_err(|_| format!("Invalid window: {}", window))?;
+    match unit {
+        "d" => Ok(count),
+        "y" => Ok(count * 365.0),
+        _ => Err(format!("Unknown window unit '{}' (use d or y)", unit)),
+    }
+}
+
 pub fn run() {
     let cli = Cli::parse();
+    let conn = db::connect().expect("Failed to connect to database");
     match cli.command {
         Commands::Song(song_args) => {
             let path = song_args.path.to_string_lossy().to_string();
@@ -98,8 +295,8 @@ pub fn run() {
                                     eprintln!("Song tagging only supports '=' operator, got: {}", tag_arg);
                                     continue;
                                 }
-                                db::add_tag(&name).expect("Failed to add tag");
-                                db::tag_song(&path, &name, value).expect("Failed to tag");
+                                db::add_tag(&conn, &name).expect("Failed to add tag");
+                                db::tag_song(&conn, &path, &name, value).expect("Failed to tag");
                             }
                             Err(error) => {
                                 eprintln!("Error parsing tag: {}", error);
@@ -108,10 +305,10 @@ pub fn run() {
                     }
                 }
                 Some(SongSubcommand::Add { path }) => {
-                    db::add_song(&path).expect(&format!("Failed to add song @ {path}"));
+                    db::add_song(&conn, &path).expect(&format!("Failed to add song @ {path}"));
                 }
                 Some(SongSubcommand::Remove { path }) => {
-                    db::remove_song(&path).expect(&format!("Failed to remove song @ {path}"));
+                    db::remove_song(&conn, &path).expect(&format!("Failed to remove song @ {path}"));
                 }
                 None => {
                     eprintln!("No song subcommand specified, exiting...");
@@ -121,13 +318,62 @@ pub fn run() {
         Commands::Tag { tag: _ } => {
             println!("Tag command stub");
         }
-        Commands::Export { tags } => {
+        Commands::Export { tags, format, output, relative } => {
             if tags.is_empty() {
                 eprintln!("No tag conditions specified");
                 return;
             }
 
-            // Parse tag conditions
+            // Parse the tag condition expression (AND/OR/parentheses)
+            let cond = match parse_cond_expr(&tags.join(" ")) {
+                Ok(cond) => cond,
+                Err(error) => {
+                    eprintln!("Error parsing tag expression: {}", error);
+                    return;
+                }
+            };
+
+            // Query the database
+            match db::query_songs(&conn, &cond) {
+                Ok(songs) => {
+                    if songs.is_empty() {
+                        println!("No songs found matching the specified conditions");
+                        return;
+                    }
+
+                    let base_dir = output
+                        .as_deref()
+                        .and_then(Path::parent)
+                        .filter(|p| !p.as_os_str().is_empty())
+                        .unwrap_or_else(|| Path::new("."));
+                    let cwd = std::env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
+                    let base_dir = resolve_path(&cwd, base_dir);
+                    let playlist = build_playlist(&songs, format, &base_dir, relative);
+
+                    match &output {
+                        Some(path) => match std::fs::write(path, &playlist) {
+                            Ok(()) => println!("Wrote {} songs to {}", songs.len(), path.display()),
+                            Err(error) => eprintln!("Failed to write playlist: {}", error),
+                        },
+                        None if matches!(format, ExportFormat::Text) => {
+                            println!("Found {} songs:", songs.len());
+                            print!("{}", playlist);
+                        }
+                        None => print!("{}", playlist),
+                    }
+                }
+                Err(error) => {
+                    eprintln!("Database error: {}", error);
+                }
+            }
+        }
+        Commands::Sql { query, limit, write } => {
+            match db::run_sql(&conn, &query, limit, write) {
+                Ok(result) => print_sql_result(&result),
+                Err(error) => eprintln!("Database error: {}", error),
+            }
+        }
+        Commands::Recommend { tags, count, window, halflife, random } => {
             let mut conditions = Vec::new();
             for tag_condition in tags {
                 match parse_tag_condition(&tag_condition) {
@@ -139,22 +385,225 @@ pub fn run() {
                 }
             }
 
-            // Query the database
-            match db::query_songs(&conditions) {
+            let window_days = match parse_window_days(&window) {
+                Ok(days) => days,
+                Err(error) => {
+                    eprintln!("Error parsing window: {}", error);
+                    return;
+                }
+            };
+
+            match db::recommend(&conn, &conditions, count, window_days, halflife, random) {
                 Ok(songs) => {
                     if songs.is_empty() {
                         println!("No songs found matching the specified conditions");
                     } else {
-                        println!("Found {} songs:", songs.len());
                         for song in songs {
                             println!("{}", song.path);
                         }
                     }
                 }
+                Err(error) => eprintln!("Database error: {}", error),
+            }
+        }
+        Commands::Scan { dir, prune } => {
+            match db::sync_library(&conn, &dir, prune) {
+                Ok(report) => {
+                    println!(
+                        "Scanned {}: {} added, {} removed, {} unchanged",
+                        dir.display(), report.added, report.removed, report.unchanged
+                    );
+                }
+                Err(error) => eprintln!("Database error: {}", error),
+            }
+        }
+        Commands::Play { tags, skip, feedback } => {
+            if tags.is_empty() {
+                eprintln!("No tag conditions specified");
+                return;
+            }
+
+            let query_text = tags.join(" ");
+            let cond = match parse_cond_expr(&query_text) {
+                Ok(cond) => cond,
                 Err(error) => {
-                    eprintln!("Database error: {}", error);
+                    eprintln!("Error parsing tag expression: {}", error);
+                    return;
+                }
+            };
+
+            let mut feedback_tags = Vec::new();
+            for arg in &feedback {
+                match parse_feedback(arg) {
+                    Ok(pair) => feedback_tags.push(pair),
+                    Err(error) => {
+                        eprintln!("Error parsing feedback: {}", error);
+                        return;
+                    }
+                }
+            }
+
+            match db::query_songs(&conn, &cond) {
+                Ok(songs) => {
+                    if songs.is_empty() {
+                        println!("No songs found matching the specified conditions");
+                        return;
+                    }
+
+                    let context_id = db::start_context(&conn, &query_text).expect("Failed to start context");
+                    for song in &songs {
+                        let timestamp = db::now(&conn).expect("Failed to read timestamp");
+                        let play_event_id = db::log_play(&conn, context_id, song.id, &timestamp, &timestamp, skip)
+                            .expect("Failed to log play");
+                        for (tag_name, sign) in &feedback_tags {
+                            if let Err(error) = db::record_feedback(&conn, play_event_id, tag_name, *sign) {
+                                eprintln!("Database error: {}", error);
+                            }
+                        }
+                        println!("{}", song.path);
+                    }
+                    println!("Logged {} play(s) in context {}", songs.len(), context_id);
                 }
+                Err(error) => eprintln!("Database error: {}", error),
             }
         }
     }
 }
+
+// Serialize matched songs into a playlist file body for the given format
+fn build_playlist(songs: &[db::Song], format: ExportFormat, base_dir: &Path, relative: bool) -> String {
+    let paths: Vec<String> = songs
+        .iter()
+        .map(|song| if relative { relativize(&song.path, base_dir) } else { song.path.clone() })
+        .collect();
+
+    match format {
+        ExportFormat::Text => paths.join("\n") + "\n",
+        ExportFormat::M3u | ExportFormat::M3u8 => {
+            let mut out = String::from("#EXTM3U\n");
+            for path in &paths {
+                let filename = Path::new(path)
+                    .file_name()
+                    .map(|f| f.to_string_lossy().to_string())
+                    .unwrap_or_else(|| path.clone());
+                out.push_str(&format!("#EXTINF:-1,{}\n{}\n", filename, path));
+            }
+            out
+        }
+        ExportFormat::Pls => {
+            let mut out = String::from("[playlist]\n");
+            for (i, path) in paths.iter().enumerate() {
+                out.push_str(&format!("File{}={}\n", i + 1, path));
+            }
+            out.push_str(&format!("NumberOfEntries={}\n", paths.len()));
+            out.push_str("Version=2\n");
+            out
+        }
+        ExportFormat::Json => {
+            let entries: Vec<String> = paths
+                .iter()
+                .map(|p| format!("  \"{}\"", p.replace('\\', "\\\\").replace('"', "\\\"")))
+                .collect();
+            format!("[\n{}\n]\n", entries.join(",\n"))
+        }
+    }
+}
+
+// Lexically resolve `path` against `base` (joining + collapsing "." and ".." components)
+// without touching the filesystem, so a relative `--output` resolves to something
+// comparable against the library's absolute song paths.
+fn resolve_path(base: &Path, path: &Path) -> PathBuf {
+    let joined = if path.is_absolute() { path.to_path_buf() } else { base.join(path) };
+
+    let mut resolved = PathBuf::new();
+    for component in joined.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            other => resolved.push(other.as_os_str()),
+        }
+    }
+    resolved
+}
+
+// Compute `path` relative to `base_dir` by comparing path components (no filesystem
+// access); falls back to the original path if they share no common ancestor.
+fn relativize(path: &str, base_dir: &Path) -> String {
+    let target = Path::new(path);
+    let base_components: Vec<_> = base_dir.components().collect();
+    let target_components: Vec<_> = target.components().collect();
+
+    let common = base_components
+        .iter()
+        .zip(target_components.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    if common == 0 {
+        return path.to_string();
+    }
+
+    let mut relative = PathBuf::new();
+    for _ in common..base_components.len() {
+        relative.push("..");
+    }
+    for component in &target_components[common..] {
+        relative.push(component.as_os_str());
+    }
+
+    relative.to_string_lossy().to_string()
+}
+
+// Pretty-print a `db::SqlResult` as a column-aligned table
+fn print_sql_result(result: &db::SqlResult) {
+    if result.columns.is_empty() {
+        println!("Query returned no columns");
+        return;
+    }
+
+    let mut widths: Vec<usize> = result.columns.iter().map(|c| c.len()).collect();
+    for row in &result.rows {
+        for (i, value) in row.iter().enumerate() {
+            widths[i] = widths[i].max(value.len());
+        }
+    }
+
+    let print_row = |cells: &[String]| {
+        let line: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(c, w)| format!("{:<width$}", c, width = w))
+            .collect();
+        println!("{}", line.join(" | "));
+    };
+
+    print_row(&result.columns);
+    println!("{}", widths.iter().map(|w| "-".repeat(*w)).collect::<Vec<_>>().join("-+-"));
+    for row in &result.rows {
+        print_row(row);
+    }
+    println!("{} row(s)", result.rows.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_bare_output_shortens_absolute_song_path() {
+        // --output playlist.m3u has no directory component, so base_dir is "."
+        let cwd = Path::new("/home/user/music");
+        let base_dir = resolve_path(cwd, Path::new("."));
+        assert_eq!(relativize("/home/user/music/sub/a.mp3", &base_dir), "sub/a.mp3");
+    }
+
+    #[test]
+    fn relative_output_in_subdir_walks_up_to_cwd() {
+        // --output out/playlist.m3u has a relative directory component
+        let cwd = Path::new("/home/user/music");
+        let base_dir = resolve_path(cwd, Path::new("out"));
+        assert_eq!(relativize("/home/user/music/a.mp3", &base_dir), "../a.mp3");
+    }
+}
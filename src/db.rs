@@ -1,14 +1,20 @@
 use rusqlite::{params, Connection, Result, OptionalExtension};
+use rand::Rng;
 use std::path::Path;
 
 
-// connect to the db
+// connect to the db, enabling WAL mode and the indexes query_songs' JOINs rely on
 pub fn connect() -> Result<Connection> {
     let db_path = "music.db";
     let conn = Connection::open(db_path)?;
 
     conn.execute_batch(
-        "BEGIN;
+        "PRAGMA journal_mode=WAL;
+        PRAGMA synchronous=NORMAL;
+        PRAGMA foreign_keys=ON;
+        PRAGMA mmap_size=268435456;
+
+        BEGIN;
         CREATE TABLE IF NOT EXISTS songs (
             id INTEGER PRIMARY KEY,
             path TEXT NOT NULL UNIQUE
@@ -53,6 +59,10 @@ pub fn connect() -> Result<Connection> {
             FOREIGN KEY(play_event_id) REFERENCES play_events(id),
             FOREIGN KEY(tag_id) REFERENCES tags(id)
         );
+
+        CREATE INDEX IF NOT EXISTS idx_song_tags_tag_value ON song_tags(tag_id, value);
+        CREATE INDEX IF NOT EXISTS idx_play_events_song ON play_events(song_id);
+        CREATE INDEX IF NOT EXISTS idx_feedback_play_event ON feedback(play_event_id);
         COMMIT;"
     )?;
 
@@ -67,30 +77,26 @@ pub struct Song {
 }
 
 // idempotent song add
-pub fn add_song(path: &str) -> Result<()> {
-    let conn = connect()?;
+pub fn add_song(conn: &Connection, path: &str) -> Result<()> {
     conn.execute("INSERT OR IGNORE INTO songs (path) VALUES (?1)", params![path])?;
     Ok(())
 }
 
 // idempotent tag add
-pub fn add_tag(name: &str) -> Result<()> {
-    let conn = connect()?;
+pub fn add_tag(conn: &Connection, name: &str) -> Result<()> {
     conn.execute("INSERT OR IGNORE INTO tags (name) VALUES (?1)", params![name])?;
     Ok(())
 }
 
 // Remove song and all its relationships
-pub fn remove_song(path: &str) -> Result<()> {
-    let conn = connect()?;
-    
+pub fn remove_song(conn: &Connection, path: &str) -> Result<()> {
     // Get the song ID first
     let song_id: Option<i64> = conn.query_row(
-        "SELECT id FROM songs WHERE path = ?1", 
-        params![path], 
+        "SELECT id FROM songs WHERE path = ?1",
+        params![path],
         |row| row.get(0)
     ).optional()?;
-    
+
     if let Some(id) = song_id {
         // Remove all tag relationships for this song
         conn.execute("DELETE FROM song_tags WHERE song_id = ?1", params![id])?;
@@ -101,19 +107,17 @@ pub fn remove_song(path: &str) -> Result<()> {
         // Finally remove the song
         conn.execute("DELETE FROM songs WHERE id = ?1", params![id])?;
     }
-    
+
     Ok(())
 }
 
-pub fn remove_tag(name: &str) -> Result<()> {
-    let conn = connect()?;
-    
+pub fn remove_tag(conn: &Connection, name: &str) -> Result<()> {
     let tag_id: Option<i64> = conn.query_row(
-        "SELECT id FROM tags WHERE name = ?1", 
-        params![name], 
+        "SELECT id FROM tags WHERE name = ?1",
+        params![name],
         |row| row.get(0)
     ).optional()?;
-    
+
     if let Some(id) = tag_id {
         // Remove all song-tag relationships
         conn.execute("DELETE FROM song_tags WHERE tag_id = ?1", params![id])?;
@@ -122,12 +126,11 @@ pub fn remove_tag(name: &str) -> Result<()> {
         // Remove the tag itself
         conn.execute("DELETE FROM tags WHERE id = ?1", params![id])?;
     }
-    
+
     Ok(())
 }
 
-pub fn tag_song(song_path: &str, tag_name: &str, value: u8) -> Result<()> {
-    let conn = connect()?;
+pub fn tag_song(conn: &Connection, song_path: &str, tag_name: &str, value: u8) -> Result<()> {
     let song_id: i64 = conn.query_row(
         "SELECT id FROM songs WHERE path = ?1",
         params![song_path],
@@ -149,52 +152,404 @@ pub fn tag_song(song_path: &str, tag_name: &str, value: u8) -> Result<()> {
     Ok(())
 }
 
+// Start a context row recording the query text that produced a set of plays
+pub fn start_context(conn: &Connection, query: &str) -> Result<i64> {
+    conn.execute("INSERT INTO contexts (query) VALUES (?1)", params![query])?;
+    Ok(conn.last_insert_rowid())
+}
+
+// Log a play_event for a song within a context, returning its id so feedback can
+// be attached to it
+pub fn log_play(conn: &Connection, context_id: i64, song_id: i64, started: &str, ended: &str, skipped: bool) -> Result<i64> {
+    conn.execute(
+        "INSERT INTO play_events (song_id, context_id, started_at, ended_at, skipped) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![song_id, context_id, started, ended, skipped],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+// Attach +1/-1 feedback to a play event for the tag that drove its selection,
+// creating the tag (as the `song tag` command does) if it doesn't exist yet
+pub fn record_feedback(conn: &Connection, play_event_id: i64, tag_name: &str, sign: i64) -> Result<()> {
+    if sign != 1 && sign != -1 {
+        return Err(rusqlite::Error::InvalidParameterName(format!("Feedback must be +1 or -1, got: {}", sign)));
+    }
+
+    add_tag(conn, tag_name)?;
+    let tag_id: i64 = conn.query_row("SELECT id FROM tags WHERE name = ?1", params![tag_name], |row| row.get(0))?;
+    conn.execute(
+        "INSERT INTO feedback (play_event_id, tag_id, feedback) VALUES (?1, ?2, ?3)",
+        params![play_event_id, tag_id, sign],
+    )?;
+
+    Ok(())
+}
+
+// Current UTC timestamp in the same format SQLite's CURRENT_TIMESTAMP produces
+pub fn now(conn: &Connection) -> Result<String> {
+    conn.query_row("SELECT CURRENT_TIMESTAMP", [], |row| row.get(0))
+}
+
+// Audio file extensions recognized by `scan`
+const AUDIO_EXTENSIONS: &[&str] = &["mp3", "flac", "wav", "ogg", "m4a", "aac", "opus", "wma"];
+
+// Summary of a `sync_library` run
+pub struct SyncReport {
+    pub added: usize,
+    pub removed: usize,
+    pub unchanged: usize,
+}
+
+// Recursively collect audio file paths under `dir`
+fn walk_audio_files(dir: &Path, out: &mut Vec<String>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            walk_audio_files(&path, out)?;
+        } else if path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| AUDIO_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+            .unwrap_or(false)
+        {
+            out.push(path.to_string_lossy().to_string());
+        }
+    }
+    Ok(())
+}
+
+// Walk `root`, add every audio file found (reusing `add_song`), and - if `prune` is
+// set - remove every songs row whose path no longer exists on disk, cascading
+// through song_tags/play_events/feedback the same way `remove_song` already does.
+pub fn sync_library(conn: &Connection, root: &Path, prune: bool) -> Result<SyncReport> {
+    let mut found = Vec::new();
+    walk_audio_files(root, &mut found)
+        .map_err(|e| rusqlite::Error::InvalidParameterName(format!("Failed to scan {}: {}", root.display(), e)))?;
+
+    let mut added = 0;
+    let mut unchanged = 0;
+    for path in &found {
+        let already_existed = conn
+            .query_row("SELECT 1 FROM songs WHERE path = ?1", params![path], |_| Ok(()))
+            .optional()?
+            .is_some();
+
+        add_song(conn, path)?;
+
+        if already_existed {
+            unchanged += 1;
+        } else {
+            added += 1;
+        }
+    }
+
+    let mut removed = 0;
+    if prune {
+        let mut stmt = conn.prepare("SELECT path FROM songs")?;
+        let db_paths: Vec<String> = stmt.query_map([], |row| row.get(0))?.collect::<Result<Vec<_>>>()?;
+
+        for path in db_paths {
+            if !Path::new(&path).exists() {
+                remove_song(conn, &path)?;
+                removed += 1;
+            }
+        }
+    }
+
+    Ok(SyncReport { added, removed, unchanged })
+}
+
+// Column headers plus stringified rows for the `sql` command's table output
+pub struct SqlResult {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+// Render a SQLite value as a display string
+fn format_value(value: &rusqlite::types::Value) -> String {
+    match value {
+        rusqlite::types::Value::Null => "NULL".to_string(),
+        rusqlite::types::Value::Integer(i) => i.to_string(),
+        rusqlite::types::Value::Real(f) => f.to_string(),
+        rusqlite::types::Value::Text(s) => s.clone(),
+        rusqlite::types::Value::Blob(b) => format!("<{} bytes>", b.len()),
+    }
+}
+
+// Run an arbitrary SQL statement. Only SELECT/PRAGMA are allowed unless `write` is set.
+pub fn run_sql(conn: &Connection, sql: &str, limit: i64, write: bool) -> Result<SqlResult> {
+    let first_word = sql.split_whitespace().next().unwrap_or("").to_uppercase();
+    if !write && first_word != "SELECT" && first_word != "PRAGMA" {
+        return Err(rusqlite::Error::InvalidParameterName(format!(
+            "Refusing to run a {} statement without --write",
+            if first_word.is_empty() { "blank" } else { &first_word }
+        )));
+    }
+
+    let mut stmt = conn.prepare(sql)?;
+    let columns: Vec<String> = stmt.column_names().iter().map(|c| c.to_string()).collect();
+    let column_count = columns.len();
+
+    let mut rows = Vec::new();
+    let mut query_rows = stmt.query([])?;
+    while let Some(row) = query_rows.next()? {
+        if limit >= 0 && rows.len() as i64 >= limit {
+            break;
+        }
+        let mut values = Vec::with_capacity(column_count);
+        for i in 0..column_count {
+            let value: rusqlite::types::Value = row.get(i)?;
+            values.push(format_value(&value));
+        }
+        rows.push(values);
+    }
+
+    Ok(SqlResult { columns, rows })
+}
+
+// A candidate song paired with its recommendation score
+struct ScoredSong {
+    song: Song,
+    score: f64,
+}
+
+// Penalty subtracted (after recency decay) for a skipped play
+const SKIP_PENALTY: f64 = 1.0;
+
+// Score and weighted-sample `count` songs out of the tag-matching candidates.
+// `window_days`/`halflife_days` control how play history decays; `random` bypasses
+// weighting entirely and samples uniformly.
+pub fn recommend(
+    conn: &Connection,
+    conditions: &[(String, u8, String)],
+    count: usize,
+    window_days: f64,
+    halflife_days: f64,
+    random: bool,
+) -> Result<Vec<Song>> {
+    let candidates = query_songs(conn, &Cond::and_of(conditions))?;
+    if candidates.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut scored = Vec::with_capacity(candidates.len());
+    for song in candidates {
+        let score = if random {
+            0.0
+        } else {
+            tag_affinity(conn, song.id, conditions)? + play_recency_score(conn, song.id, window_days, halflife_days)?
+        };
+        scored.push(ScoredSong { song, score });
+    }
+
+    Ok(weighted_sample(scored, count, random))
+}
+
+// Sum of the matched tag values for a song, used as its base tag-affinity score
+fn tag_affinity(conn: &Connection, song_id: i64, conditions: &[(String, u8, String)]) -> Result<f64> {
+    let mut total = 0.0;
+    for (tag_name, _, _) in conditions {
+        let value: Option<i64> = conn.query_row(
+            "SELECT st.value FROM song_tags st JOIN tags t ON st.tag_id = t.id
+             WHERE st.song_id = ?1 AND t.name = ?2",
+            params![song_id, tag_name],
+            |row| row.get(0),
+        ).optional()?;
+        total += value.unwrap_or(0) as f64;
+    }
+    Ok(total)
+}
+
+// Recency-decayed play/feedback score: for each play_event within `window_days`,
+// add feedback_sum * exp(-Δt / halflife_days); skipped plays subtract SKIP_PENALTY instead.
+fn play_recency_score(conn: &Connection, song_id: i64, window_days: f64, halflife_days: f64) -> Result<f64> {
+    let mut stmt = conn.prepare(
+        "SELECT pe.id, pe.skipped, julianday('now') - julianday(pe.ended_at) AS age_days
+         FROM play_events pe
+         WHERE pe.song_id = ?1 AND pe.ended_at IS NOT NULL
+           AND julianday('now') - julianday(pe.ended_at) <= ?2",
+    )?;
+
+    let plays: Vec<(i64, bool, f64)> = stmt
+        .query_map(params![song_id, window_days], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut score = 0.0;
+    for (play_event_id, skipped, age_days) in plays {
+        let decay = (-age_days / halflife_days).exp();
+
+        if skipped {
+            score -= SKIP_PENALTY * decay;
+            continue;
+        }
+
+        let feedback_sum: i64 = conn.query_row(
+            "SELECT COALESCE(SUM(feedback), 0) FROM feedback WHERE play_event_id = ?1",
+            params![play_event_id],
+            |row| row.get(0),
+        )?;
+        score += feedback_sum as f64 * decay;
+    }
+
+    Ok(score)
+}
+
+// Efraimidis-Spirakis weighted reservoir sampling without replacement.
+// `random` shuffles uniformly instead of weighting by score.
+fn weighted_sample(scored: Vec<ScoredSong>, count: usize, random: bool) -> Vec<Song> {
+    let mut rng = rand::thread_rng();
+
+    if random {
+        let mut songs: Vec<Song> = scored.into_iter().map(|s| s.song).collect();
+        let len = songs.len();
+        for i in (1..len).rev() {
+            let j = rng.gen_range(0..=i);
+            songs.swap(i, j);
+        }
+        songs.truncate(count);
+        return songs;
+    }
+
+    // Scores can be negative (net skips); shift so every weight is positive.
+    let min_score = scored.iter().map(|s| s.score).fold(f64::INFINITY, f64::min);
+    let shift = if min_score < 0.0 { -min_score + 1e-6 } else { 0.0 };
+
+    let mut keyed: Vec<(f64, Song)> = scored
+        .into_iter()
+        .map(|s| {
+            let weight = (s.score + shift).max(1e-9);
+            let u: f64 = rng.gen_range(1e-9..1.0);
+            (u.powf(1.0 / weight), s.song)
+        })
+        .collect();
+
+    keyed.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+    keyed.truncate(count);
+    keyed.into_iter().map(|(_, song)| song).collect()
+}
+
+// A boolean tag-condition expression tree: leaves are the (tag_name, value, operator)
+// triples `parse_tag_condition` already produces; And/Or combine sub-expressions.
+pub enum Cond {
+    Leaf(String, u8, String),
+    And(Vec<Cond>),
+    Or(Vec<Cond>),
+}
+
+impl Cond {
+    // Build a flat AND of leaf conditions, e.g. from commands that don't need the
+    // export grammar's OR/parentheses.
+    pub fn and_of(conditions: &[(String, u8, String)]) -> Cond {
+        Cond::And(
+            conditions
+                .iter()
+                .map(|(name, value, op)| Cond::Leaf(name.clone(), *value, op.clone()))
+                .collect(),
+        )
+    }
+}
+
+fn check_operator(operator: &str) -> Result<()> {
+    let valid_operators = ["=", ">", "<", ">=", "<=", "!="];
+    if !valid_operators.contains(&operator) {
+        return Err(rusqlite::Error::InvalidParameterName(format!("Invalid operator: {}", operator)));
+    }
+    Ok(())
+}
+
+// Flatten nested top-level And nodes into a list of terms. Leaves among them keep the
+// fast per-condition JOIN path in `query_songs`; Or (or re-nested And) subtrees fall
+// back to an EXISTS subquery.
+fn flatten_and(cond: &Cond) -> Vec<&Cond> {
+    match cond {
+        Cond::And(terms) => terms.iter().flat_map(flatten_and).collect(),
+        other => vec![other],
+    }
+}
+
+// Compile a Cond subtree into a self-contained `EXISTS (...)` boolean expression
+// against the outer query's `s.id`, using its own song_tags/tags aliases.
+fn compile_exists(cond: &Cond, alias_counter: &mut usize, params: &mut Vec<String>) -> Result<String> {
+    match cond {
+        Cond::Leaf(tag_name, value, operator) => {
+            check_operator(operator)?;
+            let i = *alias_counter;
+            *alias_counter += 1;
+            params.push(tag_name.clone());
+            params.push(value.to_string());
+            Ok(format!(
+                "EXISTS (SELECT 1 FROM song_tags st{} JOIN tags t{} ON st{}.tag_id = t{}.id \
+                 WHERE st{}.song_id = s.id AND t{}.name = ? AND st{}.value {} ?)",
+                i, i, i, i, i, i, i, operator
+            ))
+        }
+        Cond::And(terms) => {
+            let parts = terms
+                .iter()
+                .map(|t| compile_exists(t, alias_counter, params))
+                .collect::<Result<Vec<_>>>()?;
+            Ok(format!("({})", parts.join(" AND ")))
+        }
+        Cond::Or(terms) => {
+            let parts = terms
+                .iter()
+                .map(|t| compile_exists(t, alias_counter, params))
+                .collect::<Result<Vec<_>>>()?;
+            Ok(format!("({})", parts.join(" OR ")))
+        }
+    }
+}
+
 // builds and sends the query
-pub fn query_songs(conditions: &[(String, u8, String)]) -> Result<Vec<Song>> {
-    if conditions.is_empty() {
+pub fn query_songs(conn: &Connection, cond: &Cond) -> Result<Vec<Song>> {
+    let top_terms = flatten_and(cond);
+    if top_terms.is_empty() {
         return Ok(Vec::new());
     }
 
-    let conn = connect()?;
-    
-    // Build dynamic query with multiple JOINs - scales to any number of conditions
+    // Build dynamic query with multiple JOINs for plain leaves - scales to any number
+    // of AND'd conditions; OR groups fall back to EXISTS subqueries.
     let mut query = String::from("SELECT DISTINCT s.id, s.path FROM songs s");
     let mut where_conditions = Vec::new();
     let mut params_vec = Vec::new();
-    
-    for (i, (tag_name, value, operator)) in conditions.iter().enumerate() {
-        // TODO: actual input sanitization
-        let valid_operators = ["=", ">", "<", ">=", "<=", "!="];
-        if !valid_operators.contains(&operator.as_str()) {
-            return Err(rusqlite::Error::InvalidParameterName(
-                format!("Invalid operator: {}", operator)
-            ));
+    let mut alias_counter = 0usize;
+
+    for term in top_terms {
+        match term {
+            Cond::Leaf(tag_name, value, operator) => {
+                // TODO: actual input sanitization
+                check_operator(operator)?;
+
+                // Add JOIN clauses - each condition gets its own alias (st0, t0, st1, t1, etc.)
+                let i = alias_counter;
+                alias_counter += 1;
+                query.push_str(&format!(
+                    " JOIN song_tags st{} ON s.id = st{}.song_id JOIN tags t{} ON st{}.tag_id = t{}.id",
+                    i, i, i, i, i
+                ));
+
+                where_conditions.push(format!("(t{}.name = ? AND st{}.value {} ?)", i, i, operator));
+                params_vec.push(tag_name.clone());
+                params_vec.push(value.to_string());
+            }
+            other => {
+                where_conditions.push(compile_exists(other, &mut alias_counter, &mut params_vec)?);
+            }
         }
-        
-        // Add JOIN clauses - each condition gets its own alias (st0, t0, st1, t1, etc.)
-        query.push_str(&format!(
-            " JOIN song_tags st{} ON s.id = st{}.song_id JOIN tags t{} ON st{}.tag_id = t{}.id",
-            i, i, i, i, i
-        ));
-        
-        // Add WHERE condition
-        where_conditions.push(format!("(t{}.name = ? AND st{}.value {} ?)", i, i, operator));
-        
-        // Add parameters in order: tag_name, value
-        params_vec.push(tag_name.clone());
-        params_vec.push(value.to_string());
-    }
-    
+    }
+
     // Combine all WHERE conditions with AND
     query.push_str(" WHERE ");
     query.push_str(&where_conditions.join(" AND "));
     query.push_str(" ORDER BY s.path");
-    
+
     // Convert params to the format rusqlite expects
     let params: Vec<&dyn rusqlite::ToSql> = params_vec.iter()
         .map(|p| p as &dyn rusqlite::ToSql)
         .collect();
-    
+
     let mut stmt = conn.prepare(&query)?;
     let song_iter = stmt.query_map(&params[..], |row| {
         Ok(Song {